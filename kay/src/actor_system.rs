@@ -2,13 +2,25 @@ use embedded::Embedded;
 use swarm::Swarm;
 use messaging::{Message, MessagePacket, Recipient};
 use inbox::Inbox;
+use networking::{Networking, MachineID, decode_packet};
+use chunk_storage::{ChunkStorage, HeapChunkStorage};
+use attenuation::AttenuatedID;
+use bincode;
+use serde::{Serialize, Deserialize};
 use std::ops::{Deref, DerefMut};
+use std::net::TcpStream;
+use std::panic::{self, AssertUnwindSafe};
+use std::marker::PhantomData;
+use std::rc::Rc;
 
-#[derive(Copy, Clone)]
+// `machine_id` is a plain `u8`, so it round-trips through bincode like the
+// rest of `ID`'s fields with no custom (de)serialization needed.
+#[derive(Copy, Clone, Debug, Serialize, Deserialize)]
 pub struct ID {
     pub type_id: u16,
     pub version: u8,
-    pub instance_id: u32
+    pub instance_id: u32,
+    pub machine_id: MachineID
 }
 
 impl ID {
@@ -16,7 +28,8 @@ impl ID {
         ID {
             type_id: u16::max_value(),
             version: u8::max_value(),
-            instance_id: u32::max_value()
+            instance_id: u32::max_value(),
+            machine_id: MachineID::max_value()
         }
     }
 }
@@ -25,11 +38,47 @@ pub trait Known {
     fn type_id() -> usize;
 }
 
+/// Called once an actor's `is_still_embedded()` turns false, just before
+/// its slot is freed. Register via `ActorSystem::add_reapable_swarm`.
+pub trait ExitHook {
+    fn exit_hook(&mut self, world: &mut SystemServices);
+}
+
+/// A reference to an actor of a statically known type `A`, as opposed to
+/// the bare, untyped `ID`.
+pub struct TypedID<A: Known> {
+    raw: ID,
+    _marker: PhantomData<A>
+}
+
+impl<A: Known> TypedID<A> {
+    pub fn from_raw(raw: ID) -> TypedID<A> {
+        TypedID{raw: raw, _marker: PhantomData}
+    }
+
+    pub fn raw(&self) -> ID {
+        self.raw
+    }
+}
+
+impl<A: Known> Copy for TypedID<A> {}
+impl<A: Known> Clone for TypedID<A> {
+    fn clone(&self) -> TypedID<A> {
+        *self
+    }
+}
+
 pub struct LivingActor<Actor: Embedded> {
     pub id: ID,
     pub state: Actor
 }
 
+impl<Actor: Embedded + Known> LivingActor<Actor> {
+    pub fn id_typed(&self) -> TypedID<Actor> {
+        TypedID::from_raw(self.id)
+    }
+}
+
 impl<Actor: Embedded> Embedded for LivingActor<Actor> {
     fn is_still_embedded(&self) -> bool {self.state.is_still_embedded()}
     fn dynamic_size_bytes(&self) -> usize {self.state.dynamic_size_bytes()}
@@ -57,26 +106,194 @@ impl<Actor: Embedded> DerefMut for LivingActor<Actor> {
 pub struct ActorSystem {
     routing: Vec<[Option<*mut u8>; 1024]>,
     swarms: [Option<*mut u8>; 1024],
-    update_callbacks: Vec<Box<Fn()>>
+    update_callbacks: Vec<Box<Fn()>>,
+    network_decoders: Vec<Option<Box<Fn(&[u8], *mut ActorSystem)>>>,
+    machine_id: MachineID,
+    networking: Networking,
+    /// Set once a handler panics. While `true`, `process_messages` only
+    /// delivers messages whose type's `Message::is_critical()` is `true`,
+    /// so a crashed simulation can still be told to save or shut down.
+    pub panic_happened: bool,
+    storage: Rc<ChunkStorage>,
+    swarm_savers: Vec<Option<Box<Fn(*mut u8)>>>,
+    swarm_loaders: Vec<Option<Box<Fn(Rc<ChunkStorage>) -> *mut u8>>>,
+    /// Messages sent, indexed by `M::type_id()`.
+    message_statistics: Vec<u64>,
+    /// Messages delivered, indexed by the recipient swarm's `type_id()`.
+    recipient_statistics: Vec<u64>,
+    turn_end_callbacks: Vec<Box<Fn()>>,
+    reapers: Vec<Option<Box<Fn(*mut u8, *mut ActorSystem)>>>
+}
+
+/// Per-message-type send counts and per-recipient-type delivery counts, as
+/// returned by `ActorSystem::stats()`. The foundation for profiling which
+/// message types dominate a tick in large city simulations.
+pub struct Stats<'a> {
+    pub message_counts: &'a [u64],
+    pub recipient_counts: &'a [u64]
 }
 
 impl ActorSystem {
     pub fn new() -> ActorSystem {
+        ActorSystem::new_with_storage(Rc::new(HeapChunkStorage::new()))
+    }
+
+    pub fn new_with_storage(storage: Rc<ChunkStorage>) -> ActorSystem {
+        ActorSystem::new_networked_with_storage(0, Networking::new(0), storage)
+    }
+
+    pub fn new_networked(machine_id: MachineID, networking: Networking) -> ActorSystem {
+        ActorSystem::new_networked_with_storage(machine_id, networking, Rc::new(HeapChunkStorage::new()))
+    }
+
+    pub fn new_networked_with_storage(machine_id: MachineID, networking: Networking, storage: Rc<ChunkStorage>) -> ActorSystem {
         let mut type_entries = Vec::with_capacity(1024);
         for _ in 0..1024 {
             type_entries.push([None; 1024]);
         }
+        let mut network_decoders = Vec::with_capacity(1024);
+        let mut swarm_savers = Vec::with_capacity(1024);
+        let mut swarm_loaders = Vec::with_capacity(1024);
+        let mut reapers = Vec::with_capacity(1024);
+        for _ in 0..1024 {
+            network_decoders.push(None);
+            swarm_savers.push(None);
+            swarm_loaders.push(None);
+            reapers.push(None);
+        }
         ActorSystem{
             routing: type_entries,
             swarms: [None; 1024],
-            update_callbacks: Vec::new()
+            update_callbacks: Vec::new(),
+            network_decoders: network_decoders,
+            machine_id: machine_id,
+            networking: networking,
+            panic_happened: false,
+            storage: storage,
+            swarm_savers: swarm_savers,
+            swarm_loaders: swarm_loaders,
+            message_statistics: vec![0; 1024],
+            recipient_statistics: vec![0; 1024],
+            turn_end_callbacks: Vec::new(),
+            reapers: reapers
         }
     }
 
-    pub fn add_swarm<S: Embedded> (&mut self, swarm: Swarm<S>)
+    /// Registers a callback that fires once after every `update_callbacks`
+    /// has drained its inbox in `process_messages`, so subsystems can do
+    /// end-of-turn bookkeeping (commit batches, emit metrics) deterministically.
+    pub fn on_turn_end(&mut self, callback: Box<Fn()>) {
+        self.turn_end_callbacks.push(callback);
+    }
+
+    /// Per-message-type send counts (including messages sent to remote
+    /// machines) and per-recipient-type delivery counts since the system
+    /// was created.
+    pub fn stats(&self) -> Stats {
+        Stats{
+            message_counts: &self.message_statistics,
+            recipient_counts: &self.recipient_statistics
+        }
+    }
+
+    /// Registers a TCP connection to another machine, so that `send`ing
+    /// to an `ID` whose `machine_id` matches `peer_id` is carried over the
+    /// network instead of routed through a local inbox.
+    pub fn connect_peer(&mut self, peer_id: MachineID, stream: TcpStream) {
+        self.networking.connect(peer_id, stream);
+    }
+
+    /// Lets network packets of type `M` be deserialized and re-injected
+    /// into `M`'s inbox once they arrive. Call this for every message type
+    /// that is ever sent to a remote `ID`, after registering its inbox.
+    pub fn register_network_decoder<M: Message + 'static + ::serde::de::DeserializeOwned>(&mut self) {
+        self.network_decoders[M::type_id()] = Some(Box::new(|bytes: &[u8], system: *mut ActorSystem| {
+            match decode_packet::<M>(bytes) {
+                Ok(packet) => unsafe {
+                    (*system).inbox_for(&packet).put(packet);
+                },
+                Err(cause) => {
+                    eprintln!("Dropping malformed network packet of message type {}: {}", M::type_id(), cause);
+                }
+            }
+        }));
+    }
+
+    /// The chunk storage backend this system's swarms allocate from.
+    /// Pass it to `Swarm::new` when constructing a swarm to register.
+    pub fn storage(&self) -> Rc<ChunkStorage> {
+        self.storage.clone()
+    }
+
+    pub fn add_swarm<S: Embedded + 'static> (&mut self, swarm: Swarm<S>)
         where S : Known {
+        let type_id = S::type_id();
         // containing router is now responsible
-        self.swarms[S::type_id()] = Some(Box::into_raw(Box::new(swarm)) as *mut u8);
+        self.swarms[type_id] = Some(Box::into_raw(Box::new(swarm)) as *mut u8);
+        self.swarm_savers[type_id] = Some(Box::new(|ptr: *mut u8| {
+            unsafe {
+                (*(ptr as *mut Swarm<S>)).save();
+            }
+        }));
+        self.swarm_loaders[type_id] = Some(Box::new(|storage: Rc<ChunkStorage>| {
+            Box::into_raw(Box::new(Swarm::<S>::load(storage))) as *mut u8
+        }));
+    }
+
+    /// Like `add_swarm`, but actors whose `is_still_embedded()` turns false
+    /// get `exit_hook`ed and reaped by `reap_actors` (called every turn
+    /// from `process_messages`) instead of lingering in their slot.
+    pub fn add_reapable_swarm<S: Embedded + ExitHook + 'static>(&mut self, swarm: Swarm<S>)
+        where S : Known {
+        self.add_swarm(swarm);
+        self.reapers[S::type_id()] = Some(Box::new(|swarm_ptr: *mut u8, system: *mut ActorSystem| {
+            unsafe {
+                (*(swarm_ptr as *mut Swarm<S>)).reap(|actor: &mut LivingActor<S>| {
+                    actor.state.exit_hook(&mut SystemServices{system: system});
+                });
+            }
+        }));
+    }
+
+    /// Scans every swarm registered via `add_reapable_swarm`, and for any
+    /// actor whose `is_still_embedded()` is now false: runs its
+    /// `exit_hook`, frees its slot and bumps the slot's `version`, so stale
+    /// `ID`s with the old version are detected and dropped at `receive`
+    /// time. Called once per turn by `process_messages`; safe to call
+    /// again on demand.
+    pub fn reap_actors(&mut self) {
+        let self_ptr = self as *mut Self;
+        for type_id in 0..1024 {
+            if let (Some(swarm_ptr), &Some(ref reaper)) = (self.swarms[type_id], &self.reapers[type_id]) {
+                reaper(swarm_ptr, self_ptr);
+            }
+        }
+    }
+
+    /// Flushes every registered swarm's chunks to `self.storage`. The
+    /// `routing` table itself holds only transient, per-turn inbox
+    /// pointers that are rebuilt by `add_inbox` at startup, not actor
+    /// state, so there is nothing of it to persist.
+    pub fn save(&self) {
+        for type_id in 0..1024 {
+            if let (Some(ptr), &Some(ref saver)) = (self.swarms[type_id], &self.swarm_savers[type_id]) {
+                saver(ptr);
+            }
+        }
+    }
+
+    /// Remaps every registered swarm's chunks from `self.storage`, e.g.
+    /// after a restart, replacing their in-memory state with what was
+    /// persisted by the last `save()`.
+    pub fn load(&mut self) {
+        for type_id in 0..1024 {
+            if self.swarms[type_id].is_none() {
+                continue;
+            }
+            if let Some(ref loader) = self.swarm_loaders[type_id] {
+                self.swarms[type_id] = Some(loader(self.storage.clone()));
+            }
+        }
     }
 
     pub fn add_inbox<M: Message + 'static, S: Embedded + 'static>
@@ -88,12 +305,30 @@ impl ActorSystem {
         self.update_callbacks.push(Box::new(move || {
             unsafe {
                 for packet in (*(inbox_ptr as *mut Inbox<M>)).empty() {
-                    (*(swarm_ptr as *mut Swarm<S>))
-                        .receive(
-                            packet.recipient_id.instance_id as usize,
-                            &packet.message,
-                            &mut SystemServices{system: self_ptr}
+                    if (*self_ptr).panic_happened && !M::is_critical() {
+                        continue;
+                    }
+                    (*self_ptr).recipient_statistics[S::type_id()] += 1;
+                    let recipient_id = packet.recipient_id;
+                    let result = panic::catch_unwind(AssertUnwindSafe(|| {
+                        // Looked up on every delivery rather than captured once,
+                        // since `load` replaces the swarm registered for `S`.
+                        let swarm_ptr = (*self_ptr).swarms[S::type_id()].unwrap();
+                        (*(swarm_ptr as *mut Swarm<S>))
+                            .receive(
+                                recipient_id.instance_id as usize,
+                                recipient_id.version,
+                                &packet.message,
+                                &mut SystemServices{system: self_ptr}
+                            );
+                    }));
+                    if let Err(cause) = result {
+                        (*self_ptr).panic_happened = true;
+                        eprintln!(
+                            "Actor {:?} (type {}) panicked while handling message type {}: {:?}",
+                            recipient_id, S::type_id(), M::type_id(), cause
                         );
+                    }
                 }
             }
         }))
@@ -132,18 +367,51 @@ impl ActorSystem {
         }
     }
 
-    pub fn send<M: Message>(&mut self, message: M, recipient: ID) {
+    pub fn send<M: Message + ::serde::Serialize>(&mut self, message: M, recipient: ID) {
+        self.message_statistics[M::type_id()] += 1;
         let packet = MessagePacket{
             recipient_id: recipient,
             message: message
         };
-        self.inbox_for(&packet).put(packet);
+        if recipient.machine_id != self.machine_id {
+            self.networking.enqueue(M::type_id() as u16, recipient.machine_id, &packet);
+        } else {
+            self.inbox_for(&packet).put(packet);
+        }
     }
 
     pub fn process_messages(&mut self) {
         for callback in &self.update_callbacks {
             callback();
         }
+
+        self.reap_actors();
+
+        let self_ptr = self as *mut Self;
+        for (peer_id, packets) in self.networking.sync_and_flush() {
+            for (message_type_id, bytes) in packets {
+                let index = message_type_id as usize;
+                if index >= self.network_decoders.len() {
+                    eprintln!(
+                        "Dropping packet with out-of-range message type {} from machine {}",
+                        message_type_id, peer_id
+                    );
+                    continue;
+                }
+                if let Some(ref decode) = self.network_decoders[index] {
+                    decode(&bytes, self_ptr);
+                } else {
+                    eprintln!(
+                        "Dropping packet of message type {} from machine {}: no decoder registered",
+                        message_type_id, peer_id
+                    );
+                }
+            }
+        }
+
+        for callback in &self.turn_end_callbacks {
+            callback();
+        }
     }
 }
 
@@ -153,21 +421,152 @@ pub struct SystemServices {
 }
 
 impl SystemServices {
-    pub fn send<M: Message>(&mut self, message: M, recipient: ID) {
+    pub(crate) fn new(system: *mut ActorSystem) -> SystemServices {
+        SystemServices{system: system}
+    }
+
+    pub fn send<M: Message + ::serde::Serialize>(&mut self, message: M, recipient: ID) {
         unsafe {
             (*self.system).send(message, recipient);
         }
     }
+
+    /// Like `send`, but the recipient's type is statically known, so the
+    /// compiler rejects sending a message its swarm can't receive instead
+    /// of failing with a routing-table `unwrap()` at runtime.
+    pub fn send_typed<A: Known, M: Message + ::serde::Serialize>(&mut self, message: M, recipient: TypedID<A>)
+        where A : Recipient<M> {
+        unsafe {
+            (*self.system).send(message, recipient.raw());
+        }
+    }
+
+    /// Sends through an `AttenuatedID`, running `message` through its
+    /// caveat chain left-to-right first. A caveat returning `None` drops
+    /// the send before it ever reaches `inbox_for`.
+    pub fn send_attenuated<M: Message + ::serde::Serialize + ::serde::de::DeserializeOwned>
+        (&mut self, message: M, recipient: &AttenuatedID) {
+        let mut bytes = bincode::serialize(&message).expect("could not serialize attenuated message");
+        for caveat in recipient.caveats.iter() {
+            match caveat.check(M::type_id() as u16, bytes) {
+                Some(rewritten) => bytes = rewritten,
+                None => return
+            }
+        }
+        let message: M = bincode::deserialize(&bytes).expect("could not deserialize attenuated message");
+        self.send(message, recipient.target);
+    }
+
     pub fn create<S: Embedded>(&mut self, initial_state: S) -> LivingActor<S>
         where S : Known {
         unsafe {
-            (*self.system).swarm::<S>().create(initial_state)
+            let mut living_actor = (*self.system).swarm::<S>().create(initial_state);
+            living_actor.id.machine_id = (*self.system).machine_id;
+            living_actor
         }
     }
-    pub fn start<S: Embedded>(&mut self, living_actor: LivingActor<S>)
+    pub fn start<S: Embedded>(&mut self, living_actor: LivingActor<S>) -> TypedID<S>
         where S : Known {
+        let id = living_actor.id;
         unsafe {
             (*self.system).swarm::<S>().add(&living_actor);
         }
+        TypedID::from_raw(id)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn id_round_trips_through_bincode() {
+        let id = ID {
+            type_id: 7,
+            version: 3,
+            instance_id: 42,
+            machine_id: 9
+        };
+        let bytes = bincode::serialize(&id).expect("could not serialize ID");
+        let decoded: ID = bincode::deserialize(&bytes).expect("could not deserialize ID");
+        assert_eq!(decoded.type_id, id.type_id);
+        assert_eq!(decoded.version, id.version);
+        assert_eq!(decoded.instance_id, id.instance_id);
+        assert_eq!(decoded.machine_id, id.machine_id);
+    }
+
+    #[test]
+    fn invalid_id_round_trips_too() {
+        let id = ID::invalid();
+        let bytes = bincode::serialize(&id).expect("could not serialize ID");
+        let decoded: ID = bincode::deserialize(&bytes).expect("could not deserialize ID");
+        assert_eq!(decoded.machine_id, id.machine_id);
+    }
+
+    struct SomeActor;
+    impl Known for SomeActor {
+        fn type_id() -> usize { 11 }
+    }
+
+    #[test]
+    fn typed_id_round_trips_its_raw_id_unchanged() {
+        let raw = ID {
+            type_id: 11,
+            version: 0,
+            instance_id: 5,
+            machine_id: 0
+        };
+        let typed: TypedID<SomeActor> = TypedID::from_raw(raw);
+        assert_eq!(typed.raw().instance_id, raw.instance_id);
+        assert_eq!(typed.raw().type_id, raw.type_id);
+
+        // `TypedID<A>` doesn't change the `ID` it wraps, and is `Copy` —
+        // its compile-time benefit (rejecting a `TypedID<OtherActor>`
+        // passed where a `TypedID<SomeActor>` is expected) can't itself be
+        // exercised by a runtime test; the type checker enforces it.
+        let typed_copy = typed;
+        assert_eq!(typed_copy.raw().instance_id, typed.raw().instance_id);
+    }
+
+    #[test]
+    fn stats_start_at_zero_for_every_type_id() {
+        let system = ActorSystem::new();
+        let stats = system.stats();
+        assert_eq!(stats.message_counts.len(), 1024);
+        assert_eq!(stats.recipient_counts.len(), 1024);
+        assert!(stats.message_counts.iter().all(|&count| count == 0));
+        assert!(stats.recipient_counts.iter().all(|&count| count == 0));
+    }
+
+    // Exercising the actual drop-non-critical-while-panicked behavior
+    // needs a real Inbox to push messages through and drain via `empty()`
+    // in `add_inbox`'s update callback; `Inbox` lives in a module this
+    // tree doesn't define, so only the part reachable without one is
+    // covered here: a panicked system keeps running its turn instead of
+    // getting stuck.
+    #[test]
+    fn process_messages_keeps_running_after_a_panic_is_flagged() {
+        let mut system = ActorSystem::new();
+        system.panic_happened = true;
+        system.process_messages();
+        assert!(system.panic_happened, "panic_happened must stay set until explicitly cleared");
+    }
+
+    #[test]
+    fn turn_end_callback_fires_once_per_process_messages() {
+        use std::cell::Cell;
+        use std::rc::Rc as StdRc;
+
+        let mut system = ActorSystem::new();
+        let fired = StdRc::new(Cell::new(0));
+        let fired_in_callback = fired.clone();
+        system.on_turn_end(Box::new(move || {
+            fired_in_callback.set(fired_in_callback.get() + 1);
+        }));
+
+        system.process_messages();
+        assert_eq!(fired.get(), 1);
+        system.process_messages();
+        assert_eq!(fired.get(), 2);
     }
 }
\ No newline at end of file
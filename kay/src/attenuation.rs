@@ -0,0 +1,75 @@
+use std::marker::PhantomData;
+use std::sync::Arc;
+use messaging::Message;
+use actor_system::ID;
+
+/// A predicate/rewrite over an outgoing message's serialized bytes.
+/// Returning `None` drops the send.
+pub trait Caveat {
+    fn check(&self, message_type_id: u16, bytes: Vec<u8>) -> Option<Vec<u8>>;
+}
+
+/// Only lets messages of type `M` through.
+pub struct AllowMessageType<M: Message> {
+    _marker: PhantomData<M>
+}
+
+impl<M: Message> AllowMessageType<M> {
+    pub fn new() -> AllowMessageType<M> {
+        AllowMessageType{_marker: PhantomData}
+    }
+}
+
+impl<M: Message> Caveat for AllowMessageType<M> {
+    fn check(&self, message_type_id: u16, bytes: Vec<u8>) -> Option<Vec<u8>> {
+        if message_type_id as usize == M::type_id() {
+            Some(bytes)
+        } else {
+            None
+        }
+    }
+}
+
+/// A restricted handle to `target`: sending through it runs the message
+/// through `caveats`, left-to-right, dropping the send if any caveat
+/// returns `None`. Lets a system hand a sandboxed reference to, say, UI or
+/// scripting code without exposing the raw `ID`.
+pub struct AttenuatedID {
+    pub(crate) target: ID,
+    pub(crate) caveats: Arc<Vec<Box<Caveat>>>
+}
+
+impl AttenuatedID {
+    pub fn new(target: ID, caveats: Vec<Box<Caveat>>) -> AttenuatedID {
+        AttenuatedID{target: target, caveats: Arc::new(caveats)}
+    }
+}
+
+impl Clone for AttenuatedID {
+    fn clone(&self) -> AttenuatedID {
+        AttenuatedID{target: self.target, caveats: self.caveats.clone()}
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    struct Allowed;
+    impl Message for Allowed {
+        fn type_id() -> usize { 1 }
+    }
+
+    struct Other;
+    impl Message for Other {
+        fn type_id() -> usize { 2 }
+    }
+
+    #[test]
+    fn allow_message_type_passes_its_own_type_and_drops_others() {
+        let caveat = AllowMessageType::<Allowed>::new();
+        let bytes = vec![1, 2, 3];
+        assert_eq!(caveat.check(Allowed::type_id() as u16, bytes.clone()), Some(bytes.clone()));
+        assert_eq!(caveat.check(Other::type_id() as u16, bytes), None);
+    }
+}
@@ -0,0 +1,128 @@
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::path::PathBuf;
+use std::fs::OpenOptions;
+use memmap::MmapMut;
+
+/// A growable region of memory backing a `Swarm`'s actor instances.
+pub struct Chunk {
+    pub ptr: *mut u8,
+    pub len: usize
+}
+
+/// Backend for the growable chunks that `Swarm<S>` allocates into.
+pub trait ChunkStorage {
+    fn chunk(&self, collection_name: &str, index: usize, size_bytes: usize) -> Chunk;
+    fn grow_chunk(&self, collection_name: &str, index: usize, chunk: Chunk, new_size_bytes: usize) -> Chunk;
+    fn flush(&self, collection_name: &str);
+}
+
+/// Default backend: chunks live in the process heap and are lost on exit.
+pub struct HeapChunkStorage {
+    chunks: RefCell<HashMap<(String, usize), Vec<u8>>>
+}
+
+impl HeapChunkStorage {
+    pub fn new() -> HeapChunkStorage {
+        HeapChunkStorage{chunks: RefCell::new(HashMap::new())}
+    }
+}
+
+impl ChunkStorage for HeapChunkStorage {
+    fn chunk(&self, collection_name: &str, index: usize, size_bytes: usize) -> Chunk {
+        let mut chunks = self.chunks.borrow_mut();
+        let buffer = chunks.entry((collection_name.to_string(), index))
+            .or_insert_with(|| vec![0u8; size_bytes]);
+        Chunk{ptr: buffer.as_mut_ptr(), len: buffer.len()}
+    }
+
+    fn grow_chunk(&self, collection_name: &str, index: usize, _chunk: Chunk, new_size_bytes: usize) -> Chunk {
+        let mut chunks = self.chunks.borrow_mut();
+        let buffer = chunks.get_mut(&(collection_name.to_string(), index))
+            .expect("chunk to grow must already exist");
+        buffer.resize(new_size_bytes, 0);
+        Chunk{ptr: buffer.as_mut_ptr(), len: buffer.len()}
+    }
+
+    fn flush(&self, _collection_name: &str) {}
+}
+
+/// Optional backend: chunks are memory-mapped files on disk, so
+/// `ActorSystem::save`/`load` can persist and remap swarm state across runs.
+pub struct MmapChunkStorage {
+    directory: PathBuf,
+    mappings: RefCell<HashMap<(String, usize), MmapMut>>
+}
+
+impl MmapChunkStorage {
+    pub fn new(directory: PathBuf) -> MmapChunkStorage {
+        MmapChunkStorage{directory: directory, mappings: RefCell::new(HashMap::new())}
+    }
+
+    fn path_for(&self, collection_name: &str, index: usize) -> PathBuf {
+        self.directory.join(format!("{}_{}.chunk", collection_name, index))
+    }
+}
+
+impl ChunkStorage for MmapChunkStorage {
+    fn chunk(&self, collection_name: &str, index: usize, size_bytes: usize) -> Chunk {
+        let path = self.path_for(collection_name, index);
+        let file = OpenOptions::new().read(true).write(true).create(true).open(&path)
+            .expect("could not open chunk file");
+        file.set_len(size_bytes as u64).expect("could not size chunk file");
+        let mut mapping = unsafe {
+            MmapMut::map_mut(&file).expect("could not mmap chunk file")
+        };
+        let chunk = Chunk{ptr: mapping.as_mut_ptr(), len: mapping.len()};
+        self.mappings.borrow_mut().insert((collection_name.to_string(), index), mapping);
+        chunk
+    }
+
+    fn grow_chunk(&self, collection_name: &str, index: usize, _chunk: Chunk, new_size_bytes: usize) -> Chunk {
+        self.mappings.borrow_mut().remove(&(collection_name.to_string(), index));
+        self.chunk(collection_name, index, new_size_bytes)
+    }
+
+    fn flush(&self, collection_name: &str) {
+        let mappings = self.mappings.borrow();
+        for (&(ref name, _), mapping) in mappings.iter() {
+            if name == collection_name {
+                let _ = mapping.flush();
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn chunk_is_zeroed_and_reused_across_calls() {
+        let storage = HeapChunkStorage::new();
+        let chunk = storage.chunk("actors", 0, 16);
+        assert_eq!(chunk.len, 16);
+        unsafe {
+            assert_eq!(*chunk.ptr, 0);
+            *chunk.ptr = 42;
+        }
+        let same_chunk = storage.chunk("actors", 0, 16);
+        unsafe {
+            assert_eq!(*same_chunk.ptr, 42);
+        }
+    }
+
+    #[test]
+    fn grow_chunk_preserves_existing_bytes() {
+        let storage = HeapChunkStorage::new();
+        let chunk = storage.chunk("actors", 0, 4);
+        unsafe {
+            *chunk.ptr = 7;
+        }
+        let grown = storage.grow_chunk("actors", 0, chunk, 32);
+        assert_eq!(grown.len, 32);
+        unsafe {
+            assert_eq!(*grown.ptr, 7);
+        }
+    }
+}
@@ -0,0 +1,20 @@
+use actor_system::{ID, SystemServices};
+
+/// A type that can be addressed by `type_id()` and sent between actors.
+pub trait Message {
+    fn type_id() -> usize;
+
+    /// Whether this message must still reach its recipient once
+    /// `ActorSystem::panic_happened` is set. Defaults to `false`.
+    fn is_critical() -> bool { false }
+}
+
+pub trait Recipient<M: Message> {
+    fn receive(&mut self, message: &M, world: &mut SystemServices);
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct MessagePacket<M: Message> {
+    pub recipient_id: ID,
+    pub message: M
+}
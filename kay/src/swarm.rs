@@ -0,0 +1,392 @@
+use std::marker::PhantomData;
+use std::mem::size_of;
+use std::ptr;
+use std::rc::Rc;
+use embedded::Embedded;
+use messaging::{Message, Recipient};
+use actor_system::{ID, Known, LivingActor, SystemServices};
+use chunk_storage::{Chunk, ChunkStorage};
+
+const INITIAL_CAPACITY: usize = 1024;
+const INITIAL_DYNAMIC_BYTES: usize = 64 * 1024;
+const HEADER_SIZE_BYTES: usize = 16;
+
+/// Growable-chunk storage for `LivingActor<S>` instances, backed by a
+/// `ChunkStorage`. Each slot also tracks a `version`, bumped whenever the
+/// actor occupying it is reaped, so a stale `ID` pointing at a reused
+/// `instance_id` is rejected at `receive` time.
+pub struct Swarm<S: Embedded> {
+    collection_name: String,
+    storage: Rc<ChunkStorage>,
+    fixed_chunk: Chunk,
+    dynamic_chunk: Chunk,
+    header_chunk: Chunk,
+    version_chunk: Chunk,
+    occupied_chunk: Chunk,
+    item_size_bytes: usize,
+    dynamic_used_bytes: usize,
+    slot_versions: Vec<u8>,
+    occupied: Vec<bool>,
+    capacity: usize,
+    _marker: PhantomData<S>
+}
+
+impl<S: Embedded + Known> Swarm<S> {
+    pub fn new(storage: Rc<ChunkStorage>) -> Swarm<S> {
+        let collection_name = format!("swarm_{}", S::type_id());
+        let item_size_bytes = size_of::<LivingActor<S>>();
+        let fixed_chunk = storage.chunk(&Self::fixed_collection(&collection_name), 0, item_size_bytes * INITIAL_CAPACITY);
+        let dynamic_chunk = storage.chunk(&Self::dynamic_collection(&collection_name), 0, INITIAL_DYNAMIC_BYTES);
+        let header_chunk = storage.chunk(&Self::header_collection(&collection_name), 0, HEADER_SIZE_BYTES);
+        let version_chunk = storage.chunk(&Self::version_collection(&collection_name), 0, INITIAL_CAPACITY);
+        let occupied_chunk = storage.chunk(&Self::occupied_collection(&collection_name), 0, INITIAL_CAPACITY);
+        Swarm{
+            collection_name: collection_name,
+            storage: storage,
+            fixed_chunk: fixed_chunk,
+            dynamic_chunk: dynamic_chunk,
+            header_chunk: header_chunk,
+            version_chunk: version_chunk,
+            occupied_chunk: occupied_chunk,
+            item_size_bytes: item_size_bytes,
+            dynamic_used_bytes: 0,
+            slot_versions: vec![0; INITIAL_CAPACITY],
+            occupied: vec![false; INITIAL_CAPACITY],
+            capacity: INITIAL_CAPACITY,
+            _marker: PhantomData
+        }
+    }
+
+    fn fixed_collection(collection_name: &str) -> String {
+        format!("{}_fixed", collection_name)
+    }
+
+    fn dynamic_collection(collection_name: &str) -> String {
+        format!("{}_dynamic", collection_name)
+    }
+
+    fn header_collection(collection_name: &str) -> String {
+        format!("{}_header", collection_name)
+    }
+
+    fn version_collection(collection_name: &str) -> String {
+        format!("{}_version", collection_name)
+    }
+
+    fn occupied_collection(collection_name: &str) -> String {
+        format!("{}_occupied", collection_name)
+    }
+
+    fn slot_ptr(&self, instance_id: usize) -> *mut LivingActor<S> {
+        unsafe {
+            (self.fixed_chunk.ptr as *mut LivingActor<S>).add(instance_id)
+        }
+    }
+
+    fn allocate_slot(&mut self) -> usize {
+        match self.occupied.iter().position(|&taken| !taken) {
+            Some(instance_id) => instance_id,
+            None => {
+                self.grow_fixed();
+                self.allocate_slot()
+            }
+        }
+    }
+
+    /// Doubles the fixed-part chunk's capacity. If the backing storage
+    /// relocates the chunk to a new base address, every still-occupied
+    /// actor is moved across via `embed_from`, exactly as a memory-mapped
+    /// remap would require.
+    fn grow_fixed(&mut self) {
+        let new_capacity = self.capacity * 2;
+        let old_chunk_ptr = self.fixed_chunk.ptr;
+        let old_chunk = Chunk{ptr: self.fixed_chunk.ptr, len: self.fixed_chunk.len};
+        let new_chunk = self.storage.grow_chunk(
+            &Self::fixed_collection(&self.collection_name), 0, old_chunk, self.item_size_bytes * new_capacity
+        );
+        if new_chunk.ptr != old_chunk_ptr {
+            for instance_id in 0..self.capacity {
+                if self.occupied[instance_id] {
+                    unsafe {
+                        // `grow_chunk` already copied the old bytes into `new_chunk` at the
+                        // same offset, so relocate from there instead of `old_chunk_ptr`,
+                        // which storage backends like `HeapChunkStorage` may have freed.
+                        let relocated_slot = (new_chunk.ptr as *mut LivingActor<S>).add(instance_id);
+                        let relocated = ptr::read(relocated_slot);
+                        (*relocated_slot).embed_from(&relocated, self.dynamic_chunk.ptr);
+                    }
+                }
+            }
+        }
+        self.fixed_chunk = new_chunk;
+
+        let old_version_chunk = Chunk{ptr: self.version_chunk.ptr, len: self.version_chunk.len};
+        self.version_chunk = self.storage.grow_chunk(
+            &Self::version_collection(&self.collection_name), 0, old_version_chunk, new_capacity
+        );
+        let old_occupied_chunk = Chunk{ptr: self.occupied_chunk.ptr, len: self.occupied_chunk.len};
+        self.occupied_chunk = self.storage.grow_chunk(
+            &Self::occupied_collection(&self.collection_name), 0, old_occupied_chunk, new_capacity
+        );
+
+        self.slot_versions.resize(new_capacity, 0);
+        self.occupied.resize(new_capacity, false);
+        self.capacity = new_capacity;
+    }
+
+    /// Grows the dynamic-part chunk to fit `extra_bytes` more, relocating
+    /// every occupied actor's dynamic part via `embed_from` if the chunk
+    /// moved.
+    fn reserve_dynamic(&mut self, extra_bytes: usize) {
+        if self.dynamic_used_bytes + extra_bytes <= self.dynamic_chunk.len {
+            return;
+        }
+        let old_chunk = Chunk{ptr: self.dynamic_chunk.ptr, len: self.dynamic_chunk.len};
+        let old_ptr = self.dynamic_chunk.ptr;
+        let new_len = (self.dynamic_chunk.len * 2).max(self.dynamic_used_bytes + extra_bytes);
+        let new_chunk = self.storage.grow_chunk(&Self::dynamic_collection(&self.collection_name), 0, old_chunk, new_len);
+        if new_chunk.ptr != old_ptr {
+            for instance_id in 0..self.capacity {
+                if self.occupied[instance_id] {
+                    unsafe {
+                        let slot = self.slot_ptr(instance_id);
+                        let previous = ptr::read(slot);
+                        (*slot).embed_from(&previous, new_chunk.ptr);
+                    }
+                }
+            }
+        }
+        self.dynamic_chunk = new_chunk;
+    }
+
+    /// Allocates a fresh slot and returns a `LivingActor` addressed to it.
+    /// Call `add` to actually place it into the swarm's chunks.
+    pub fn create(&mut self, initial_state: S) -> LivingActor<S> {
+        let instance_id = self.allocate_slot();
+        // Reserved here, not in `add`, so two `create()` calls before either
+        // result is `add()`ed can't be handed the same `instance_id`.
+        self.occupied[instance_id] = true;
+        LivingActor{
+            id: ID{
+                type_id: S::type_id() as u16,
+                version: self.slot_versions[instance_id],
+                instance_id: instance_id as u32,
+                machine_id: 0
+            },
+            state: initial_state
+        }
+    }
+
+    /// Places a `LivingActor` (from `create`) into its addressed slot.
+    pub fn add(&mut self, living_actor: &LivingActor<S>) {
+        let instance_id = living_actor.id.instance_id as usize;
+        self.reserve_dynamic(living_actor.state.dynamic_size_bytes());
+        unsafe {
+            ptr::write(self.slot_ptr(instance_id), ptr::read(living_actor));
+        }
+        self.dynamic_used_bytes += living_actor.state.dynamic_size_bytes();
+    }
+
+    /// Dispatches `message` to the actor at `instance_id`, unless its slot
+    /// is empty or `version` doesn't match the slot's current version —
+    /// either way, `id` is stale, most likely pointing at an actor that
+    /// has since been reaped and whose slot was handed to another actor.
+    pub fn receive<M: Message>(&mut self, instance_id: usize, version: u8, message: &M, world: &mut SystemServices)
+        where S : Recipient<M> {
+        if instance_id >= self.capacity || !self.occupied[instance_id] || self.slot_versions[instance_id] != version {
+            return;
+        }
+        unsafe {
+            (*self.slot_ptr(instance_id)).state.receive(message, world);
+        }
+    }
+
+    /// Scans every occupied slot and, for any actor whose
+    /// `is_still_embedded()` has turned false: calls `on_exit`, frees the
+    /// slot and bumps its `version` so a stale `ID` referencing the old
+    /// version is rejected by `receive` once the slot is handed to
+    /// another actor.
+    pub fn reap<F: FnMut(&mut LivingActor<S>)>(&mut self, mut on_exit: F) {
+        for instance_id in 0..self.capacity {
+            if !self.occupied[instance_id] {
+                continue;
+            }
+            let still_embedded = unsafe { (*self.slot_ptr(instance_id)).is_still_embedded() };
+            if !still_embedded {
+                unsafe {
+                    on_exit(&mut *self.slot_ptr(instance_id));
+                }
+                self.occupied[instance_id] = false;
+                self.slot_versions[instance_id] = self.slot_versions[instance_id].wrapping_add(1);
+            }
+        }
+    }
+
+    /// Writes `slot_versions`/`occupied`/`capacity` into their own chunks
+    /// and flushes every chunk to the backing storage, so `load` can restore
+    /// not just the actor bytes but which slots are live and at what version.
+    pub fn save(&self) {
+        unsafe {
+            ptr::copy_nonoverlapping(self.slot_versions.as_ptr(), self.version_chunk.ptr, self.capacity);
+            for (instance_id, &occupied) in self.occupied.iter().enumerate() {
+                *self.occupied_chunk.ptr.add(instance_id) = occupied as u8;
+            }
+            let header = self.header_chunk.ptr as *mut u64;
+            ptr::write(header, self.capacity as u64);
+            ptr::write(header.add(1), self.dynamic_chunk.len as u64);
+        }
+        self.storage.flush(&Self::fixed_collection(&self.collection_name));
+        self.storage.flush(&Self::dynamic_collection(&self.collection_name));
+        self.storage.flush(&Self::header_collection(&self.collection_name));
+        self.storage.flush(&Self::version_collection(&self.collection_name));
+        self.storage.flush(&Self::occupied_collection(&self.collection_name));
+    }
+
+    /// Remaps this swarm's chunks from `storage`, replacing its in-memory
+    /// state with what was last `save`d, including which slots are
+    /// occupied and at what version, so `receive`/`create` against the
+    /// reloaded swarm behave as if it had never been unloaded.
+    pub fn load(storage: Rc<ChunkStorage>) -> Swarm<S> {
+        let collection_name = format!("swarm_{}", S::type_id());
+        let item_size_bytes = size_of::<LivingActor<S>>();
+
+        // Read the small, fixed-size header first so the other chunks can be
+        // requested at their real persisted sizes: `MmapChunkStorage::chunk`
+        // truncates a collection to whatever size is asked for, so requesting
+        // the wrong (e.g. default) size here would silently drop saved data.
+        let header_chunk = storage.chunk(&Self::header_collection(&collection_name), 0, HEADER_SIZE_BYTES);
+        let (capacity, dynamic_len) = unsafe {
+            let header = header_chunk.ptr as *const u64;
+            (ptr::read(header) as usize, ptr::read(header.add(1)) as usize)
+        };
+        let capacity = if capacity == 0 { INITIAL_CAPACITY } else { capacity };
+        let dynamic_len = if dynamic_len == 0 { INITIAL_DYNAMIC_BYTES } else { dynamic_len };
+
+        let fixed_chunk = storage.chunk(&Self::fixed_collection(&collection_name), 0, item_size_bytes * capacity);
+        let dynamic_chunk = storage.chunk(&Self::dynamic_collection(&collection_name), 0, dynamic_len);
+        let version_chunk = storage.chunk(&Self::version_collection(&collection_name), 0, capacity);
+        let occupied_chunk = storage.chunk(&Self::occupied_collection(&collection_name), 0, capacity);
+
+        let mut slot_versions = vec![0u8; capacity];
+        let mut occupied = vec![false; capacity];
+        unsafe {
+            ptr::copy_nonoverlapping(version_chunk.ptr, slot_versions.as_mut_ptr(), capacity);
+            for instance_id in 0..capacity {
+                occupied[instance_id] = *occupied_chunk.ptr.add(instance_id) != 0;
+            }
+        }
+
+        let mut swarm = Swarm{
+            collection_name: collection_name,
+            storage: storage,
+            fixed_chunk: fixed_chunk,
+            dynamic_chunk: dynamic_chunk,
+            header_chunk: header_chunk,
+            version_chunk: version_chunk,
+            occupied_chunk: occupied_chunk,
+            item_size_bytes: item_size_bytes,
+            dynamic_used_bytes: 0,
+            slot_versions: slot_versions,
+            occupied: occupied,
+            capacity: capacity,
+            _marker: PhantomData
+        };
+        // Not itself persisted: recomputed from the restored actors, since
+        // `reap` doesn't track freed dynamic regions individually either.
+        swarm.dynamic_used_bytes = (0..capacity)
+            .filter(|&instance_id| swarm.occupied[instance_id])
+            .map(|instance_id| unsafe { (*swarm.slot_ptr(instance_id)).state.dynamic_size_bytes() })
+            .sum();
+        swarm
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chunk_storage::HeapChunkStorage;
+
+    struct TestActor {
+        alive: bool,
+        tag: u32
+    }
+
+    impl Embedded for TestActor {
+        fn is_still_embedded(&self) -> bool { self.alive }
+        fn dynamic_size_bytes(&self) -> usize { 0 }
+        unsafe fn embed_from(&mut self, other: &Self, _new_dynamic_part: *mut u8) {
+            self.alive = other.alive;
+            self.tag = other.tag;
+        }
+    }
+
+    impl Known for TestActor {
+        fn type_id() -> usize { 0 }
+    }
+
+    struct Ping;
+    impl Message for Ping {
+        fn type_id() -> usize { 0 }
+    }
+    impl Recipient<Ping> for TestActor {
+        fn receive(&mut self, _message: &Ping, _world: &mut SystemServices) {
+            self.tag += 1;
+        }
+    }
+
+    #[test]
+    fn reap_frees_slot_and_bumps_version_only_once_not_embedded() {
+        let storage = Rc::new(HeapChunkStorage::new());
+        let mut swarm: Swarm<TestActor> = Swarm::new(storage);
+        let actor = swarm.create(TestActor{alive: true, tag: 1});
+        let id = actor.id;
+        swarm.add(&actor);
+
+        let mut exits = 0;
+        swarm.reap(|_| exits += 1);
+        assert_eq!(exits, 0, "a still-embedded actor must not be reaped");
+        assert!(swarm.occupied[id.instance_id as usize]);
+
+        unsafe {
+            (*swarm.slot_ptr(id.instance_id as usize)).state.alive = false;
+        }
+        swarm.reap(|_| exits += 1);
+        assert_eq!(exits, 1);
+        assert!(!swarm.occupied[id.instance_id as usize]);
+        assert_eq!(swarm.slot_versions[id.instance_id as usize], id.version.wrapping_add(1));
+
+        swarm.reap(|_| exits += 1);
+        assert_eq!(exits, 1, "a freed slot must not be reaped again");
+    }
+
+    #[test]
+    fn save_then_load_preserves_occupancy_and_is_reachable_via_receive() {
+        use actor_system::ActorSystem;
+
+        let storage = Rc::new(HeapChunkStorage::new());
+        let mut swarm: Swarm<TestActor> = Swarm::new(storage.clone());
+        let actor = swarm.create(TestActor{alive: true, tag: 1});
+        let id = actor.id;
+        swarm.add(&actor);
+        swarm.save();
+
+        let mut loaded: Swarm<TestActor> = Swarm::load(storage);
+        let mut system = ActorSystem::new();
+        let mut world = SystemServices::new(&mut system as *mut ActorSystem);
+        loaded.receive(id.instance_id as usize, id.version, &Ping, &mut world);
+        unsafe {
+            assert_eq!(
+                (*loaded.slot_ptr(id.instance_id as usize)).state.tag, 2,
+                "receive must still reach the actor after a save/load cycle"
+            );
+        }
+
+        // If `load` hadn't restored `occupied`, this would silently reuse
+        // the still-live slot instead of allocating a fresh one.
+        let other = loaded.create(TestActor{alive: true, tag: 99});
+        assert_ne!(
+            other.id.instance_id, id.instance_id,
+            "load must restore occupancy, not reset it, or create() clobbers a live actor"
+        );
+    }
+}